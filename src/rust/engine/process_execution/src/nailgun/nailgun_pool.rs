@@ -1,12 +1,12 @@
 // Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
 
-use std::io::{self, BufRead, Read};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::os::unix::process::ExitStatusExt;
 use std::path::{Path, PathBuf};
 use std::process::Stdio;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex as StdMutex, Weak};
 use std::time::{Duration, Instant};
 
 use async_lock::{Mutex, MutexGuardArc};
@@ -17,6 +17,7 @@ use regex::Regex;
 use store::Store;
 use task_executor::Executor;
 use tempfile::TempDir;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt};
 
 use crate::local::prepare_workdir;
 use crate::{Context, MultiPlatformProcess, NamedCaches, Process, ProcessMetadata};
@@ -28,17 +29,59 @@ lazy_static! {
 struct PoolEntry {
   fingerprint: NailgunProcessFingerprint,
   last_used: Instant,
-  process: Arc<Mutex<NailgunProcess>>,
+  reservation: Reservation,
+}
+
+///
+/// How a PoolEntry hands its underlying NailgunProcess out to callers.
+///
+/// `Unique` is the original, exclusive model: a single caller owns the process for the duration
+/// of its use, and the entry is unavailable to anyone else until it is released. `Shared` allows
+/// up to `max_concurrency` callers to use the same nailgun server at once: rather than an
+/// exclusive lock, usage is tracked with an atomic in-flight counter.
+///
+enum Reservation {
+  Unique(Arc<Mutex<NailgunProcess>>),
+  Shared {
+    process: Arc<NailgunProcess>,
+    max_concurrency: usize,
+    in_flight: Arc<AtomicUsize>,
+  },
+}
+
+///
+/// A reservation that has actually been claimed by a caller, and will be returned to them as a
+/// `BorrowedNailgunProcess`.
+///
+enum ActiveReservation {
+  Unique(MutexGuardArc<NailgunProcess>),
+  Shared(Arc<NailgunProcess>, Arc<AtomicUsize>),
 }
 
 pub type Port = u16;
 
+///
+/// A queue of killed-but-not-yet-reaped child processes, borrowed from tokio's own process
+/// module. Reaping a `tokio::process::Child` (awaiting its exit) is itself async and
+/// non-blocking, but `Drop` impls can't `.await`; so instead of blocking in `Drop`, we push the
+/// child onto this queue (a plain, synchronous operation) and let a background task reap it.
+///
+#[derive(Clone, Default)]
+struct OrphanQueue(Arc<StdMutex<Vec<tokio::process::Child>>>);
+
+impl OrphanQueue {
+  fn push(&self, child: tokio::process::Child) {
+    self.0.lock().unwrap().push(child);
+  }
+}
+
 ///
 /// A NailgunPool contains a small Vec of running NailgunProcess instances, fingerprinted with the
 /// request used to start them.
 ///
 /// Mutations of the Vec are protected by a Mutex, but each NailgunProcess is also protected by its
-/// own Mutex, which is used to track when the process is in use.
+/// own Reservation, which is used to track when the process is in use (exclusively, or up to some
+/// concurrency limit).
 ///
 /// NB: This pool expects to be used under a semaphore with size equal to the pool size. Because of
 /// this, it never actually waits for a pool entry to complete, and can instead assume that at
@@ -51,46 +94,170 @@ pub struct NailgunPool {
   store: Store,
   executor: Executor,
   named_caches: NamedCaches,
+  startup_timeout: Duration,
+  liveness_probe_timeout: Option<Duration>,
+  orphans: OrphanQueue,
   processes: Arc<Mutex<Vec<PoolEntry>>>,
 }
 
 impl NailgunPool {
+  ///
+  /// If `max_idle_duration` is provided, a background task is spawned which periodically scans
+  /// the pool and kills+removes any entry which has been idle for longer than that duration. The
+  /// task holds only a Weak reference to the pool's process list, so it exits on its own once the
+  /// pool (and thus the last strong reference) is dropped.
+  ///
+  /// `startup_timeout` bounds how long a newly spawned nailgun server is given to report its port
+  /// before we give up on it: see `NailgunProcess::start_new`.
+  ///
+  /// `liveness_probe_timeout`, if provided, makes liveness checks actively attempt a TCP connect
+  /// to the server's port (rather than trusting the OS process handle alone) before handing out a
+  /// reservation, treating a refused/timed-out connection as dead. This costs a TCP connection
+  /// attempt for each pooled server checked, so it's opt-in.
+  ///
   pub fn new(
     workdir_base: PathBuf,
     size: usize,
     store: Store,
     executor: Executor,
     named_caches: NamedCaches,
+    max_idle_duration: Option<Duration>,
+    startup_timeout: Duration,
+    liveness_probe_timeout: Option<Duration>,
   ) -> Self {
+    let processes: Arc<Mutex<Vec<PoolEntry>>> = Arc::default();
+    if let Some(max_idle_duration) = max_idle_duration {
+      Self::spawn_reaper(executor.clone(), Arc::downgrade(&processes), max_idle_duration);
+    }
+    let orphans = OrphanQueue::default();
+    Self::spawn_orphan_reaper(executor.clone(), Arc::downgrade(&orphans.0));
     NailgunPool {
       workdir_base,
       size,
       store,
       executor,
       named_caches,
-      processes: Arc::default(),
+      liveness_probe_timeout,
+      startup_timeout,
+      orphans,
+      processes,
     }
   }
 
+  ///
+  /// Spawn the idle-eviction reaper task described on `new`. Borrows hyper's pool-reaper pattern:
+  /// wake up on an interval, and reap anything that has been idle for too long.
+  ///
+  fn spawn_reaper(
+    executor: Executor,
+    processes: Weak<Mutex<Vec<PoolEntry>>>,
+    max_idle_duration: Duration,
+  ) {
+    // NB: We don't need to scan any more often than the TTL itself, but scanning too rarely would
+    // let idle servers overstay their welcome by up to a full interval: split the difference.
+    let scan_interval = (max_idle_duration / 2).max(Duration::from_secs(1));
+    let _ = executor.spawn(async move {
+      let mut interval = tokio::time::interval(scan_interval);
+      loop {
+        interval.tick().await;
+        let processes = match processes.upgrade() {
+          Some(processes) => processes,
+          None => return,
+        };
+        let mut processes = processes.lock().await;
+        let now = Instant::now();
+        let mut idx = 0;
+        while idx < processes.len() {
+          let entry = &processes[idx];
+          let expired = now.saturating_duration_since(entry.last_used) >= max_idle_duration;
+          let idle = match &entry.reservation {
+            Reservation::Unique(process) => process.try_lock_arc().is_some(),
+            Reservation::Shared { in_flight, .. } => in_flight.load(Ordering::SeqCst) == 0,
+          };
+          if expired && idle {
+            debug!(
+              "Reaping idle nailgun server {:?} after {:?} of idleness.",
+              entry.fingerprint.name,
+              now.saturating_duration_since(entry.last_used)
+            );
+            processes.swap_remove(idx);
+          } else {
+            idx += 1;
+          }
+        }
+      }
+    });
+  }
+
+  ///
+  /// Spawn the orphan-reaping task described on `OrphanQueue`: periodically drain the queue, and
+  /// reap each child concurrently via its own `wait().await`, rather than blocking any one thread
+  /// on it.
+  ///
+  fn spawn_orphan_reaper(
+    executor: Executor,
+    orphans: Weak<StdMutex<Vec<tokio::process::Child>>>,
+  ) {
+    let reaper_executor = executor.clone();
+    let _ = executor.spawn(async move {
+      let mut interval = tokio::time::interval(Duration::from_millis(250));
+      loop {
+        interval.tick().await;
+        let orphans = match orphans.upgrade() {
+          Some(orphans) => orphans,
+          None => return,
+        };
+        let pending = std::mem::take(&mut *orphans.lock().unwrap());
+        for mut child in pending {
+          let executor = reaper_executor.clone();
+          let _ = executor.spawn(async move {
+            let _ = child.wait().await;
+          });
+        }
+      }
+    });
+  }
+
   ///
   /// Given a name and a `Process` configuration, return a port of a nailgun server running
   /// under that name and configuration.
   ///
   /// If the server is not running, or if it's running with a different configuration,
-  /// this code will start a new server as a side effect.
+  /// this code will start a new server as a side effect. `max_concurrency` is the caller-supplied
+  /// number of clients the server supports handling at once; passing a value greater than 1 lets
+  /// this call race fewer exclusive `start_new` calls against other callers of the same server.
+  /// Nothing in `Process` itself declares this today, so until it grows a dedicated field,
+  /// callers are responsible for deciding and passing it explicitly (e.g. from a subsystem option
+  /// describing the target server's concurrency support).
   ///
   pub async fn acquire(
     &self,
     server_process: Process,
+    max_concurrency: usize,
     context: Context,
   ) -> Result<BorrowedNailgunProcess, String> {
     let name = server_process.description.clone();
-    let requested_fingerprint = NailgunProcessFingerprint::new(name.clone(), &server_process)?;
+    let max_concurrency = max_concurrency.max(1);
+    // NB: `max_concurrency` is threaded into the fingerprint explicitly (rather than relying on
+    // `server_process`'s own digest below to capture it) since it isn't itself a field of
+    // `Process`: the explicit `PartialEq`/`Hash` derive on `NailgunProcessFingerprint` is what
+    // guarantees that servers requested with different concurrency settings are never treated as
+    // interchangeable.
+    let requested_fingerprint =
+      NailgunProcessFingerprint::new(name.clone(), &server_process, max_concurrency)?;
     let mut processes = self.processes.lock().await;
 
-    // Start by seeing whether there are any idle processes with a matching fingerprint.
-    if let Some((_idx, process)) = Self::find_usable(&mut *processes, &requested_fingerprint)? {
-      return Ok(BorrowedNailgunProcess::new(process));
+    // Start by seeing whether there are any idle (or underutilized) processes with a matching
+    // fingerprint.
+    if let Some((_idx, reservation)) =
+      Self::find_usable(
+        &mut *processes,
+        &requested_fingerprint,
+        self.liveness_probe_timeout,
+      )
+      .await?
+    {
+      return Ok(BorrowedNailgunProcess::new(reservation));
     }
 
     // There wasn't a matching, valid, available process. We need to start one.
@@ -106,43 +273,62 @@ impl NailgunPool {
     }
 
     // Start the new process.
-    let process = Arc::new(Mutex::new(
-      NailgunProcess::start_new(
-        name.clone(),
-        server_process,
-        &self.workdir_base,
-        context,
-        &self.store,
-        self.executor.clone(),
-        &self.named_caches,
-        requested_fingerprint.clone(),
+    let process = NailgunProcess::start_new(
+      name.clone(),
+      server_process,
+      &self.workdir_base,
+      context,
+      &self.store,
+      self.executor.clone(),
+      &self.named_caches,
+      requested_fingerprint.clone(),
+      self.startup_timeout,
+      self.orphans.clone(),
+    )
+    .await?;
+
+    let (reservation, active) = if max_concurrency > 1 {
+      let process = Arc::new(process);
+      let in_flight = Arc::new(AtomicUsize::new(1));
+      (
+        Reservation::Shared {
+          process: process.clone(),
+          max_concurrency,
+          in_flight: in_flight.clone(),
+        },
+        ActiveReservation::Shared(process, in_flight),
       )
-      .await?,
-    ));
+    } else {
+      let process = Arc::new(Mutex::new(process));
+      let guard = process.lock_arc().await;
+      (Reservation::Unique(process), ActiveReservation::Unique(guard))
+    };
+
     processes.push(PoolEntry {
       fingerprint: requested_fingerprint,
       last_used: Instant::now(),
-      process: process.clone(),
+      reservation,
     });
 
-    Ok(BorrowedNailgunProcess::new(process.lock_arc().await))
+    Ok(BorrowedNailgunProcess::new(active))
   }
 
   ///
   /// Find a usable process in the pool that matches the given fingerprint.
   ///
-  fn find_usable(
+  async fn find_usable(
     pool_entries: &mut Vec<PoolEntry>,
     fingerprint: &NailgunProcessFingerprint,
-  ) -> Result<Option<(usize, MutexGuardArc<NailgunProcess>)>, String> {
+    liveness_probe_timeout: Option<Duration>,
+  ) -> Result<Option<(usize, ActiveReservation)>, String> {
     let mut dead_processes = Vec::new();
     for (idx, pool_entry) in pool_entries.iter_mut().enumerate() {
       if &pool_entry.fingerprint != fingerprint {
         continue;
       }
 
-      match Self::try_use(pool_entry)? {
-        TryUse::Usable(process) => return Ok(Some((idx, process))),
+      match Self::try_use(pool_entry, liveness_probe_timeout).await? {
+        TryUse::Usable(reservation) => return Ok(Some((idx, reservation))),
         TryUse::Dead => dead_processes.push(idx),
         TryUse::Busy => continue,
       }
@@ -162,7 +348,11 @@ impl NailgunPool {
     let mut lru_age = Instant::now() + Duration::from_secs(60 * 60 * 24);
     let mut lru = None;
     for (idx, pool_entry) in pool_entries.iter_mut().enumerate() {
-      if pool_entry.process.try_lock_arc().is_some() && pool_entry.last_used < lru_age {
+      let is_idle = match &pool_entry.reservation {
+        Reservation::Unique(process) => process.try_lock_arc().is_some(),
+        Reservation::Shared { in_flight, .. } => in_flight.load(Ordering::SeqCst) == 0,
+      };
+      if is_idle && pool_entry.last_used < lru_age {
         lru = Some(idx);
         lru_age = pool_entry.last_used;
       }
@@ -170,82 +360,187 @@ impl NailgunPool {
     Ok(lru)
   }
 
-  fn try_use(pool_entry: &mut PoolEntry) -> Result<TryUse, String> {
-    let mut process = if let Some(process) = pool_entry.process.try_lock_arc() {
-      process
-    } else {
-      return Ok(TryUse::Busy);
-    };
+  async fn try_use(
+    pool_entry: &mut PoolEntry,
+    liveness_probe_timeout: Option<Duration>,
+  ) -> Result<TryUse, String> {
+    match &pool_entry.reservation {
+      Reservation::Unique(process_lock) => {
+        let process = if let Some(process) = process_lock.try_lock_arc() {
+          process
+        } else {
+          return Ok(TryUse::Busy);
+        };
+
+        match process.check_liveness(liveness_probe_timeout).await? {
+          Liveness::Alive => {
+            pool_entry.last_used = Instant::now();
+            Ok(TryUse::Usable(ActiveReservation::Unique(process)))
+          }
+          Liveness::Dead => Ok(TryUse::Dead),
+        }
+      }
+      Reservation::Shared {
+        process,
+        max_concurrency,
+        in_flight,
+      } => {
+        if in_flight.load(Ordering::SeqCst) >= *max_concurrency {
+          return Ok(TryUse::Busy);
+        }
+        // NB: Unlike the `Unique` case, a shared entry may have other concurrent borrowers, so
+        // this check_liveness call locks `handle` only for the duration of the check, rather than
+        // taking it (and thus the server) out of circulation for other callers.
+        match process.check_liveness(liveness_probe_timeout).await? {
+          Liveness::Alive => {
+            pool_entry.last_used = Instant::now();
+            in_flight.fetch_add(1, Ordering::SeqCst);
+            Ok(TryUse::Usable(ActiveReservation::Shared(
+              process.clone(),
+              in_flight.clone(),
+            )))
+          }
+          Liveness::Dead => Ok(TryUse::Dead),
+        }
+      }
+    }
+  }
+}
 
-    pool_entry.last_used = Instant::now();
+enum Liveness {
+  Alive,
+  Dead,
+}
+
+enum TryUse {
+  Usable(ActiveReservation),
+  Busy,
+  Dead,
+}
+
+/// Representation of a running nailgun server.
+pub struct NailgunProcess {
+  pub name: String,
+  fingerprint: NailgunProcessFingerprint,
+  workdir: TempDir,
+  port: Port,
+  executor: task_executor::Executor,
+  orphans: OrphanQueue,
+  // NB: Guarded by a lock (rather than owned exclusively) so that a `Shared` reservation, which
+  // only ever holds a plain `Arc<NailgunProcess>`, can still check liveness without needing
+  // exclusive access to the process. `None` only momentarily, while the handle is being moved to
+  // the orphan queue in `Drop`.
+  handle: Mutex<Option<tokio::process::Child>>,
+}
 
+impl NailgunProcess {
+  ///
+  /// Checks whether this nailgun server is still alive, optionally backed by an active TCP
+  /// connect probe. Takes the handle lock for only the duration of the check, so (unlike a `&mut
+  /// NailgunProcess` borrow) this works equally well for a `Shared` reservation, which only ever
+  /// has concurrent, shared access to the process.
+  ///
+  async fn check_liveness(
+    &self,
+    liveness_probe_timeout: Option<Duration>,
+  ) -> Result<Liveness, String> {
     debug!(
       "Checking if nailgun server {} is still alive at port {}...",
-      process.name, process.port
+      self.name, self.port
     );
 
-    // Check if it's alive using the handle.
-    let status = process
-      .handle
-      .try_wait()
-      .map_err(|e| format!("Error getting the process status! {}", e))?;
+    let status = {
+      let mut handle = self.handle.lock().await;
+      handle
+        .as_mut()
+        .expect("NailgunProcess handle was already reaped.")
+        .try_wait()
+        .map_err(|e| format!("Error getting the process status! {}", e))?
+    };
     match status {
       None => {
-        // Process hasn't exited yet.
+        // The handle says the process hasn't exited, but it may still be wedged or not yet
+        // accepting connections: optionally back that up with an active connect probe, mirroring
+        // an `is_open`-style liveness check. Both the connect and the timeout around it are
+        // async, so this never blocks a worker thread while other callers are waiting on the
+        // pool's lock.
+        if let Some(timeout) = liveness_probe_timeout {
+          let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), self.port);
+          match tokio::time::timeout(timeout, tokio::net::TcpStream::connect(addr)).await {
+            Ok(Ok(_stream)) => {}
+            Ok(Err(e)) => {
+              log::warn!(
+                "Nailgun server {} refused a connection on port {}: {}",
+                self.name,
+                self.port,
+                e
+              );
+              return Ok(Liveness::Dead);
+            }
+            Err(_) => {
+              log::warn!(
+                "Nailgun server {} did not accept a connection on port {} within {:?}",
+                self.name,
+                self.port,
+                timeout
+              );
+              return Ok(Liveness::Dead);
+            }
+          }
+        }
         debug!(
           "Found nailgun process {}, with fingerprint {:?}",
-          process.name, process.fingerprint
+          self.name, self.fingerprint
         );
-        Ok(TryUse::Usable(process))
+        Ok(Liveness::Alive)
       }
       Some(status) => {
         // The process has exited with some exit code: restart it.
         if status.signal() != Some(9) {
           // TODO: BorrowedNailgunProcess cancellation uses `kill` currently, so we avoid warning
           // for that. In future it would be nice to find a better cancellation strategy.
-          log::warn!(
-            "The nailgun server for {} exited with {}.",
-            process.name,
-            status
-          );
+          log::warn!("The nailgun server for {} exited with {}.", self.name, status);
         }
-        Ok(TryUse::Dead)
+        Ok(Liveness::Dead)
       }
     }
   }
-}
 
-enum TryUse {
-  Usable(MutexGuardArc<NailgunProcess>),
-  Busy,
-  Dead,
+  ///
+  /// Signal the process to exit, without blocking. This is a sync, best-effort operation (via
+  /// `try_lock`) so that it remains usable from contexts (like `Drop`) that can't `.await` the
+  /// handle lock; the pool's reaping and fingerprinting machinery don't depend on this succeeding
+  /// promptly.
+  ///
+  fn start_kill(&self) {
+    if let Some(mut handle) = self.handle.try_lock() {
+      if let Some(child) = handle.as_mut() {
+        let _ = child.start_kill();
+      }
+    }
+  }
 }
 
-/// Representation of a running nailgun server.
-pub struct NailgunProcess {
-  pub name: String,
-  fingerprint: NailgunProcessFingerprint,
-  workdir: TempDir,
-  port: Port,
-  executor: task_executor::Executor,
-  handle: std::process::Child,
+///
+/// Read the first line that the nailgun server writes to its stdout, which is expected to report
+/// the port it's listening on. This is genuinely async (backed by tokio's non-blocking pipe I/O),
+/// so unlike a `std::process::Child` read it doesn't need a dedicated blocking thread. The
+/// `stdout` handle is taken out of the `Child` so that the `Child` itself remains available to its
+/// owner (e.g. to kill it if this read times out).
+///
+async fn read_port_line(stdout: tokio::process::ChildStdout) -> Result<String, String> {
+  let mut lines = tokio::io::BufReader::new(stdout).lines();
+  lines
+    .next_line()
+    .await
+    .map_err(|e| format!("Failed to read from stdout: {}", e))?
+    .ok_or_else(|| "There is no line ready in the child's output".to_string())
 }
 
-fn read_port(child: &mut std::process::Child) -> Result<Port, String> {
-  let stdout = child
-    .stdout
-    .as_mut()
-    .ok_or_else(|| "No stdout found!".to_string());
-  let port_line = stdout
-    .and_then(|stdout| {
-      let reader = io::BufReader::new(stdout);
-      reader
-        .lines()
-        .next()
-        .ok_or_else(|| "There is no line ready in the child's output".to_string())
-    })
-    .and_then(|res| res.map_err(|e| format!("Failed to read from stdout: {}", e)));
-
+async fn read_port(
+  child: &mut tokio::process::Child,
+  port_line: Result<String, String>,
+) -> Result<Port, String> {
   // If we failed to read a port line and the child has exited, report that.
   if port_line.is_err() {
     if let Some(exit_status) = child.try_wait().map_err(|e| e.to_string())? {
@@ -255,6 +550,7 @@ fn read_port(child: &mut std::process::Child) -> Result<Port, String> {
         .take()
         .unwrap()
         .read_to_string(&mut stderr)
+        .await
         .map_err(|e| e.to_string())?;
       return Err(format!(
         "Nailgun failed to start: exited with {}, stderr:\n{}",
@@ -273,6 +569,39 @@ fn read_port(child: &mut std::process::Child) -> Result<Port, String> {
     .map_err(|e| format!("Error parsing port {}! {}", &port, e))
 }
 
+///
+/// Wait for `child` to announce its port on stdout, bounded by `startup_timeout`. If the timeout
+/// elapses first, kill the child (and consume its stderr for the error message) before returning,
+/// so a hung startup never leaves a zombie behind it.
+///
+async fn await_startup_port(
+  child: &mut tokio::process::Child,
+  startup_timeout: Duration,
+  name: &str,
+) -> Result<Port, String> {
+  let stdout = child
+    .stdout
+    .take()
+    .ok_or_else(|| "No stdout found!".to_string())?;
+  let port_line = match tokio::time::timeout(startup_timeout, read_port_line(stdout)).await {
+    Ok(result) => result,
+    Err(_) => {
+      // Both `kill` and `wait` are async on `tokio::process::Child`, so this doesn't block the
+      // runtime while the server is torn down.
+      let _ = child.kill().await;
+      let mut stderr = String::new();
+      if let Some(mut stderr_handle) = child.stderr.take() {
+        let _ = stderr_handle.read_to_string(&mut stderr).await;
+      }
+      return Err(format!(
+        "Timed out after {:?} waiting for the nailgun server for {:?} to start. stderr:\n{}",
+        startup_timeout, name, stderr
+      ));
+    }
+  };
+  read_port(child, port_line).await
+}
+
 impl NailgunProcess {
   async fn start_new(
     name: String,
@@ -283,6 +612,8 @@ impl NailgunProcess {
     executor: Executor,
     named_caches: &NamedCaches,
     nailgun_server_fingerprint: NailgunProcessFingerprint,
+    startup_timeout: Duration,
+    orphans: OrphanQueue,
   ) -> Result<NailgunProcess, String> {
     let workdir = tempfile::Builder::new()
       .prefix("process-execution")
@@ -311,7 +642,7 @@ impl NailgunProcess {
       &startup_options.argv[1..],
       workdir.path()
     );
-    let mut child = std::process::Command::new(&cmd)
+    let mut child = tokio::process::Command::new(&cmd)
       .args(&startup_options.argv[1..])
       .stdout(Stdio::piped())
       .stderr(Stdio::piped())
@@ -324,9 +655,12 @@ impl NailgunProcess {
         )
       })?;
 
-    let port = read_port(&mut child)?;
+    // Read the server's startup port announcement, bounded by `startup_timeout`: a JVM server
+    // that hangs during boot (e.g. on classpath resolution) would otherwise wedge this future,
+    // and its caller, forever.
+    let port = await_startup_port(&mut child, startup_timeout, &name).await?;
     debug!(
-      "Created nailgun server process with pid {} and port {}",
+      "Created nailgun server process with pid {:?} and port {}",
       child.id(),
       port
     );
@@ -340,7 +674,8 @@ impl NailgunProcess {
       workdir,
       name,
       executor,
-      handle: child,
+      orphans,
+      handle: Mutex::new(Some(child)),
     })
   }
 }
@@ -348,9 +683,34 @@ impl NailgunProcess {
 impl Drop for NailgunProcess {
   fn drop(&mut self) {
     debug!("Exiting nailgun server process {:?}", self.name);
-    if self.handle.kill().is_ok() {
-      // NB: This is blocking, but should be a short wait in general.
-      let _ = self.handle.wait();
+    // NB: `Drop` can't `.await` the handle lock, but by the time `NailgunProcess` itself is being
+    // dropped there are no other `Arc<NailgunProcess>` holders left to contend it with, so
+    // `try_lock` succeeding, and finding a handle still in place, is the expected case, not
+    // best-effort: either branch of the `None`s below would mean that invariant broke somewhere,
+    // and silently swallowing that here would turn into an unnoticed leaked JVM process.
+    match self.handle.try_lock() {
+      Some(mut handle) => match handle.take() {
+        Some(mut child) => {
+          // Signal the child to exit, then hand it off to the background reaper rather than
+          // blocking this `Drop` on an async (or even sync) wait.
+          let _ = child.start_kill();
+          self.orphans.push(child);
+        }
+        None => {
+          log::warn!(
+            "Nailgun server {:?} had already been reaped when its NailgunProcess was dropped; \
+            if it still has a live child process, that process will leak.",
+            self.name
+          );
+        }
+      },
+      None => {
+        log::warn!(
+          "Could not acquire the handle lock while dropping nailgun server {:?}; its child \
+          process, if still running, will leak.",
+          self.name
+        );
+      }
     }
   }
 }
@@ -360,14 +720,20 @@ impl Drop for NailgunProcess {
 /// This is calculated by hashing together:
 ///   - The jvm options and classpath used to create the server
 ///   - The path to the jdk
+///   - The maximum number of concurrent clients the server was started to support
 #[derive(Clone, Hash, PartialEq, Eq, Debug)]
 struct NailgunProcessFingerprint {
   pub name: String,
   pub fingerprint: Fingerprint,
+  pub max_concurrency: usize,
 }
 
 impl NailgunProcessFingerprint {
-  pub fn new(name: String, nailgun_req: &Process) -> Result<Self, String> {
+  pub fn new(
+    name: String,
+    nailgun_req: &Process,
+    max_concurrency: usize,
+  ) -> Result<Self, String> {
     let nailgun_req_digest = crate::digest(
       MultiPlatformProcess::from(nailgun_req.clone()),
       &ProcessMetadata::default(),
@@ -375,27 +741,37 @@ impl NailgunProcessFingerprint {
     Ok(NailgunProcessFingerprint {
       name,
       fingerprint: nailgun_req_digest.hash,
+      max_concurrency,
     })
   }
 }
 
 ///
 /// A wrapper around a NailgunProcess checked out from the pool. If `release` is not called, the
-/// guard assumes cancellation, and kills the underlying process.
+/// guard assumes cancellation: for an exclusively reserved process, this kills the underlying
+/// process, while for a shared reservation it simply relinquishes this caller's slot (since other
+/// callers may still be using the server).
 ///
-pub struct BorrowedNailgunProcess(Option<MutexGuardArc<NailgunProcess>>);
+pub struct BorrowedNailgunProcess(Option<ActiveReservation>);
 
 impl BorrowedNailgunProcess {
-  fn new(process: MutexGuardArc<NailgunProcess>) -> Self {
-    Self(Some(process))
+  fn new(reservation: ActiveReservation) -> Self {
+    Self(Some(reservation))
+  }
+
+  fn process(&self) -> &NailgunProcess {
+    match self.0.as_ref().unwrap() {
+      ActiveReservation::Unique(process) => process,
+      ActiveReservation::Shared(process, _) => process,
+    }
   }
 
   pub fn name(&self) -> &str {
-    &self.0.as_ref().unwrap().name
+    &self.process().name
   }
 
   pub fn port(&self) -> u16 {
-    self.0.as_ref().unwrap().port
+    self.process().port
   }
 
   pub fn address(&self) -> SocketAddr {
@@ -403,7 +779,7 @@ impl BorrowedNailgunProcess {
   }
 
   pub fn workdir_path(&self) -> &Path {
-    self.0.as_ref().unwrap().workdir.path()
+    self.process().workdir.path()
   }
 
   ///
@@ -412,25 +788,48 @@ impl BorrowedNailgunProcess {
   /// Clears the working directory for the process before returning it.
   ///
   pub async fn release(&mut self) -> Result<(), String> {
-    let process = self.0.as_ref().expect("release may only be called once.");
-
-    clear_workdir(process.workdir.path(), &process.executor).await?;
+    let reservation = self
+      .0
+      .as_ref()
+      .expect("release may only be called once.");
+
+    let (workdir, executor) = match reservation {
+      ActiveReservation::Unique(process) => (process.workdir.path(), &process.executor),
+      ActiveReservation::Shared(process, _) => (process.workdir.path(), &process.executor),
+    };
+    clear_workdir(workdir, executor).await?;
 
-    // Once we've successfully cleaned up, remove the process.
-    let _ = self.0.take();
+    // Once we've successfully cleaned up, remove the reservation: for a shared process, this
+    // must decrement the in-flight count, since `Drop` (which otherwise does so on cancellation)
+    // never runs its `Shared` arm once `self.0` has been taken here.
+    if let Some(ActiveReservation::Shared(_, in_flight)) = self.0.take() {
+      in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
     Ok(())
   }
 }
 
 impl Drop for BorrowedNailgunProcess {
   fn drop(&mut self) {
-    if let Some(mut process) = self.0.take() {
-      // Kill the process, but rely on the pool to notice that it is dead and restart it.
-      debug!(
-        "Killing nailgun process {:?} due to cancellation.",
-        process.name
-      );
-      let _ = process.handle.kill();
+    match self.0.take() {
+      Some(ActiveReservation::Unique(process)) => {
+        // Signal the process to exit, but rely on the pool to notice that it is dead and restart
+        // it; reaping happens later, via the orphan queue, when the `NailgunProcess` itself drops.
+        debug!(
+          "Killing nailgun process {:?} due to cancellation.",
+          process.name
+        );
+        process.start_kill();
+      }
+      Some(ActiveReservation::Shared(process, in_flight)) => {
+        // Other callers may still be using this server: just give up our slot.
+        debug!(
+          "Releasing shared nailgun process {:?} due to cancellation.",
+          process.name
+        );
+        in_flight.fetch_sub(1, Ordering::SeqCst);
+      }
+      None => {}
     }
   }
 }
@@ -473,3 +872,144 @@ async fn clear_workdir(workdir: &Path, executor: &Executor) -> Result<(), String
 
   Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn test_fingerprint(max_concurrency: usize) -> NailgunProcessFingerprint {
+    NailgunProcessFingerprint {
+      name: "test-nailgun-server".to_owned(),
+      fingerprint: Fingerprint::from_bytes_unsafe(&[0; 32]),
+      max_concurrency,
+    }
+  }
+
+  async fn test_nailgun_process(executor: &Executor, orphans: &OrphanQueue) -> NailgunProcess {
+    let child = tokio::process::Command::new("sleep")
+      .arg("30")
+      .stdout(Stdio::null())
+      .stderr(Stdio::null())
+      .spawn()
+      .expect("failed to spawn `sleep` fixture process");
+    NailgunProcess {
+      name: "test-nailgun-server".to_owned(),
+      fingerprint: test_fingerprint(2),
+      workdir: tempfile::Builder::new()
+        .prefix("nailgun-pool-test")
+        .tempdir()
+        .unwrap(),
+      port: 0,
+      executor: executor.clone(),
+      orphans: orphans.clone(),
+      handle: Mutex::new(Some(child)),
+    }
+  }
+
+  ///
+  /// Covers the regression fixed by 1720635: a clean `release()` must decrement `in_flight`
+  /// itself, since `Drop`'s `Shared` arm (which otherwise does the decrementing) never runs once
+  /// `release()` has already taken the reservation. Also covers the cancellation path, where
+  /// `Drop` is what's expected to decrement it.
+  ///
+  #[tokio::test]
+  async fn shared_reservation_in_flight_is_decremented_by_release_and_by_drop() {
+    let executor = Executor::new();
+    let process = Arc::new(test_nailgun_process(&executor, &OrphanQueue::default()).await);
+    let in_flight = Arc::new(AtomicUsize::new(0));
+
+    in_flight.fetch_add(1, Ordering::SeqCst);
+    let mut first = BorrowedNailgunProcess::new(ActiveReservation::Shared(
+      process.clone(),
+      in_flight.clone(),
+    ));
+    in_flight.fetch_add(1, Ordering::SeqCst);
+    let second = BorrowedNailgunProcess::new(ActiveReservation::Shared(
+      process.clone(),
+      in_flight.clone(),
+    ));
+    assert_eq!(in_flight.load(Ordering::SeqCst), 2);
+
+    first.release().await.unwrap();
+    assert_eq!(in_flight.load(Ordering::SeqCst), 1);
+
+    // Cancellation (no `release()` call): `Drop` must decrement in_flight on its own.
+    drop(second);
+    assert_eq!(in_flight.load(Ordering::SeqCst), 0);
+  }
+
+  ///
+  /// The idle reaper should only evict entries that are both expired *and* idle: a `Shared` entry
+  /// with a nonzero `in_flight` count is still busy, regardless of how long ago it was last used.
+  ///
+  #[tokio::test]
+  async fn idle_reaper_evicts_expired_but_not_busy_entries() {
+    let executor = Executor::new();
+    let orphans = OrphanQueue::default();
+    let long_ago = Instant::now() - Duration::from_secs(60);
+
+    let expired_idle = PoolEntry {
+      fingerprint: test_fingerprint(2),
+      last_used: long_ago,
+      reservation: Reservation::Shared {
+        process: Arc::new(test_nailgun_process(&executor, &orphans).await),
+        max_concurrency: 2,
+        in_flight: Arc::new(AtomicUsize::new(0)),
+      },
+    };
+    let expired_but_busy = PoolEntry {
+      fingerprint: test_fingerprint(2),
+      last_used: long_ago,
+      reservation: Reservation::Shared {
+        process: Arc::new(test_nailgun_process(&executor, &orphans).await),
+        max_concurrency: 2,
+        in_flight: Arc::new(AtomicUsize::new(1)),
+      },
+    };
+
+    let processes = Arc::new(Mutex::new(vec![expired_idle, expired_but_busy]));
+    NailgunPool::spawn_reaper(
+      executor.clone(),
+      Arc::downgrade(&processes),
+      Duration::from_millis(20),
+    );
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let remaining = processes.lock().await;
+    assert_eq!(remaining.len(), 1);
+    assert_eq!(
+      match &remaining[0].reservation {
+        Reservation::Shared { in_flight, .. } => in_flight.load(Ordering::SeqCst),
+        Reservation::Unique(_) => unreachable!(),
+      },
+      1
+    );
+  }
+
+  ///
+  /// A startup that never announces its port must be killed rather than left to hang (or worse,
+  /// leaked as a zombie) forever: covers the timeout-and-kill path extracted from `start_new`.
+  ///
+  #[tokio::test]
+  async fn await_startup_port_kills_a_hanging_child_and_returns_err() {
+    let mut child = tokio::process::Command::new("sh")
+      .arg("-c")
+      .arg("sleep 30")
+      .stdout(Stdio::piped())
+      .stderr(Stdio::piped())
+      .spawn()
+      .expect("failed to spawn `sh` fixture process");
+
+    let result = await_startup_port(&mut child, Duration::from_millis(10), "test-server").await;
+    assert!(result.is_err());
+
+    // The timeout path kills the child directly (rather than deferring to the orphan queue):
+    // confirm it has actually exited, not just been signaled.
+    let status = child
+      .wait()
+      .await
+      .expect("child should be reapable after being killed");
+    assert!(!status.success());
+  }
+}